@@ -0,0 +1,223 @@
+use git2::{Cred, CredentialType};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+enum Method {
+    Agent,
+    Key(PathBuf),
+    UserPass(String),
+}
+
+/// Caches SSH agent/key and HTTPS credentials across repositories so a
+/// multi-repo run doesn't re-prompt for the same passphrase or retry a key
+/// that already failed once we know what works for a given remote URL.
+///
+/// Passwords and passphrases are kept in memory in plaintext for the life
+/// of the process, trading a bit of exposure (e.g. to a core dump) for not
+/// re-prompting on every retry or every repository that shares a remote.
+pub struct AuthCache {
+    candidate_keys: Vec<PathBuf>,
+    last_successful: Mutex<HashMap<String, Method>>,
+    passphrases: Mutex<HashMap<PathBuf, String>>,
+    // Worker threads can hit credential prompts for different repos at the
+    // same time; this keeps one prompt's write+read on the TTY from
+    // interleaving with another's.
+    prompt_lock: Mutex<()>,
+}
+
+/// Tracks whichever credential method was most recently offered to libgit2
+/// during one fetch/clone. libgit2 only invokes the credentials callback
+/// again when the previous credential it was given failed to authenticate,
+/// so once the remote operation as a whole returns `Ok`, whatever is
+/// recorded here is the method that actually worked. Pass it to
+/// `AuthCache::confirm` at that point to cache it for the next repository.
+#[derive(Default)]
+pub struct Offered(Mutex<Option<(String, Method)>>);
+
+/// Credentials callback returned by `AuthCache::credentials_callback`,
+/// paired with the `Offered` handle it records into.
+type CredentialsCallback =
+    (Box<dyn FnMut(&str, Option<&str>, CredentialType) -> Result<Cred, git2::Error>>, Arc<Offered>);
+
+impl AuthCache {
+    pub fn new(extra_keys: &[String]) -> Self {
+        let ssh_dir = PathBuf::from(format!("{}/.ssh", env::var("HOME").unwrap_or_default()));
+        let mut candidate_keys = vec![ssh_dir.join("id_ed25519"), ssh_dir.join("id_rsa")];
+        candidate_keys.extend(extra_keys.iter().map(PathBuf::from));
+        AuthCache {
+            candidate_keys,
+            last_successful: Mutex::new(HashMap::new()),
+            passphrases: Mutex::new(HashMap::new()),
+            prompt_lock: Mutex::new(()),
+        }
+    }
+
+    /// Builds a fresh `RemoteCallbacks::credentials` closure for one fetch,
+    /// plus the `Offered` handle it records into. `attempt` and `key_index`
+    /// both live in the closure, not on `self`, so two repositories
+    /// authenticating against the same host concurrently don't clobber each
+    /// other's retry state.
+    ///
+    /// `key_index` tracks how many candidate keys have actually been
+    /// offered, separately from `attempt` (libgit2's overall callback-call
+    /// count): the agent is tried on `attempt == 0` but doesn't consume a
+    /// candidate key, so indexing keys by `attempt` would skip or re-try a
+    /// key depending on whether the agent construction itself happened to
+    /// succeed that round.
+    pub fn credentials_callback(self: &Arc<Self>) -> CredentialsCallback {
+        let cache = Arc::clone(self);
+        let offered = Arc::new(Offered::default());
+        let offered_in_closure = Arc::clone(&offered);
+        let mut attempt = 0usize;
+        let mut key_index = 0usize;
+        let credentials = move |url: &str, username_from_url: Option<&str>, allowed_types: CredentialType| {
+            let result = cache.try_credentials(
+                url,
+                username_from_url,
+                allowed_types,
+                attempt,
+                &mut key_index,
+                &offered_in_closure,
+            );
+            attempt += 1;
+            result
+        };
+        (Box::new(credentials), offered)
+    }
+
+    /// Caches whichever method `offered` last recorded. Call this once the
+    /// remote operation `offered` was attached to has returned `Ok`.
+    pub fn confirm(&self, offered: &Offered) {
+        if let Some((url, method)) = offered.0.lock().unwrap().take() {
+            self.remember(&url, method);
+        }
+    }
+
+    fn try_credentials(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed_types: CredentialType,
+        attempt: usize,
+        key_index: &mut usize,
+        offered: &Offered,
+    ) -> Result<Cred, git2::Error> {
+        let username = self.username(username_from_url);
+
+        if attempt == 0 {
+            if let Some(method) = self.last_successful.lock().unwrap().get(url).cloned() {
+                if let Ok(cred) = self.try_method(&method, &username, allowed_types) {
+                    *offered.0.lock().unwrap() = Some((url.to_string(), method));
+                    return Ok(cred);
+                }
+            }
+        }
+
+        if allowed_types.contains(CredentialType::SSH_KEY) {
+            if attempt == 0 {
+                if let Ok(cred) = Cred::ssh_key_from_agent(&username) {
+                    *offered.0.lock().unwrap() = Some((url.to_string(), Method::Agent));
+                    return Ok(cred);
+                }
+            }
+            while let Some(key) = self.candidate_keys.get(*key_index).cloned() {
+                *key_index += 1;
+                if key.exists() {
+                    if let Ok(cred) = self.try_key(&username, &key) {
+                        *offered.0.lock().unwrap() = Some((url.to_string(), Method::Key(key.clone())));
+                        return Ok(cred);
+                    }
+                }
+            }
+        }
+
+        if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            let password = self.prompt(&format!("Password for {}: ", url));
+            if let Ok(cred) = Cred::userpass_plaintext(&username, &password) {
+                *offered.0.lock().unwrap() = Some((url.to_string(), Method::UserPass(password)));
+                return Ok(cred);
+            }
+        }
+
+        Err(git2::Error::from_str(&format!(
+            "no working credentials found for {}",
+            url
+        )))
+    }
+
+    fn try_method(
+        &self,
+        method: &Method,
+        username: &str,
+        allowed_types: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        match method {
+            Method::Agent if allowed_types.contains(CredentialType::SSH_KEY) => {
+                Cred::ssh_key_from_agent(username)
+            }
+            Method::Key(key) if allowed_types.contains(CredentialType::SSH_KEY) => {
+                self.try_key(username, key)
+            }
+            Method::UserPass(password) if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) => {
+                Cred::userpass_plaintext(username, password)
+            }
+            _ => Err(git2::Error::from_str("cached credential method not applicable")),
+        }
+    }
+
+    /// Tries `key` unencrypted first, so plain keys (or ones the agent
+    /// would otherwise handle) never trigger a passphrase prompt; only
+    /// falls back to prompting once that fails.
+    fn try_key(&self, username: &str, key: &Path) -> Result<Cred, git2::Error> {
+        if let Ok(cred) = Cred::ssh_key(username, None, key, None) {
+            return Ok(cred);
+        }
+        let passphrase = self.passphrase_for(key);
+        Cred::ssh_key(username, None, key, passphrase.as_deref())
+    }
+
+    fn username(&self, username_from_url: Option<&str>) -> String {
+        if let Some(username) = username_from_url {
+            return username.to_string();
+        }
+        self.prompt("Username: ")
+    }
+
+    fn passphrase_for(&self, key: &Path) -> Option<String> {
+        if let Some(cached) = self.passphrases.lock().unwrap().get(key) {
+            return Some(cached.clone());
+        }
+        let passphrase = {
+            let _guard = self.prompt_lock.lock().unwrap();
+            rpassword::prompt_password(format!("Enter passphrase for key {}: ", key.display()))
+                .ok()?
+        };
+        if !passphrase.is_empty() {
+            self.passphrases
+                .lock()
+                .unwrap()
+                .insert(key.to_path_buf(), passphrase.clone());
+        }
+        Some(passphrase)
+    }
+
+    fn prompt(&self, message: &str) -> String {
+        let _guard = self.prompt_lock.lock().unwrap();
+        print!("{}", message);
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        let _ = io::stdin().read_line(&mut line);
+        line.trim().to_string()
+    }
+
+    fn remember(&self, url: &str, method: Method) {
+        self.last_successful
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), method);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::auth::AuthCache;
+use crate::config;
+use anyhow::{Result, anyhow};
+use colored::Colorize;
+use git2::{Oid, Repository};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const SIGNATURE: &str = "# v2 git bundle";
+
+fn bundle_path(repository: &config::Repository) -> PathBuf {
+    PathBuf::from(format!("{}.bundle", repository.name()))
+}
+
+/// Writes every ref in `repository` and the full history behind it into a
+/// `<name>.bundle` file in the v2 git bundle format: the `# v2 git bundle`
+/// signature, one `oid refname` line per ref, a blank line, then the
+/// packfile. libgit2 doesn't expose bundle creation, so this assembles the
+/// format by hand around `Repository::packbuilder`.
+pub fn bundle_repo(repository: config::Repository, _auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<()> {
+    let repo = Repository::open(&repository.location)?;
+    let path = bundle_path(&repository);
+
+    let mut refs = Vec::new();
+    let mut revwalk = repo.revwalk()?;
+    for reference in repo.references()? {
+        let reference = reference?;
+        if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+            revwalk.push(oid)?;
+            refs.push((oid, name.to_string()));
+        }
+    }
+    if refs.is_empty() {
+        return Err(anyhow!("{}: no refs to bundle", repository.name()));
+    }
+
+    let mut builder = repo.packbuilder()?;
+    builder.insert_walk(&mut revwalk)?;
+    // The walk only covers commits and trees; refs naming an annotated tag
+    // need the tag object itself in the pack too.
+    for (oid, _) in &refs {
+        builder.insert_object(*oid, None)?;
+    }
+    let mut pack = git2::Buf::new();
+    builder.write_buf(&mut pack)?;
+
+    let mut file = std::fs::File::create(&path)?;
+    writeln!(file, "{}", SIGNATURE)?;
+    for (oid, name) in &refs {
+        writeln!(file, "{} {}", oid, name)?;
+    }
+    writeln!(file)?;
+    file.write_all(&pack)?;
+
+    writeln!(
+        out,
+        "{}: wrote {} ({} refs)",
+        repository.name().green().bold(),
+        path.display(),
+        refs.len(),
+    )?;
+    Ok(())
+}
+
+/// Restores refs and history from `<name>.bundle` into `repository`, after
+/// checking that any prerequisite commits (lines prefixed with `-`, for
+/// bundles created relative to a known history) already exist locally.
+pub fn unbundle_repo(repository: config::Repository, _auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<()> {
+    let repo = Repository::open(&repository.location)?;
+    let path = bundle_path(&repository);
+
+    let data = std::fs::read(&path)?;
+    let (header, mut rest) = split_line(&data)?;
+    if header != SIGNATURE {
+        return Err(anyhow!("{}: not a v2 git bundle", path.display()));
+    }
+
+    let mut prerequisites = Vec::new();
+    let mut refs = Vec::new();
+    loop {
+        let (line, remainder) = split_line(rest)?;
+        rest = remainder;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(oid) = line.strip_prefix('-') {
+            prerequisites.push(oid.trim().to_string());
+        } else {
+            let (oid, name) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("malformed bundle ref line: {}", line))?;
+            refs.push((oid.to_string(), name.to_string()));
+        }
+    }
+
+    for prerequisite in &prerequisites {
+        let oid = Oid::from_str(prerequisite)?;
+        repo.find_commit(oid)
+            .map_err(|_| anyhow!("missing prerequisite commit {}", prerequisite))?;
+    }
+
+    let odb = repo.odb()?;
+    let mut writepack = odb.packwriter()?;
+    writepack.write_all(rest)?;
+    writepack.commit()?;
+
+    for (oid, name) in &refs {
+        let oid = Oid::from_str(oid)?;
+        repo.reference(name, oid, true, "gat: unbundle")?;
+    }
+
+    writeln!(
+        out,
+        "{}: restored {} refs from {}",
+        repository.name().green().bold(),
+        refs.len(),
+        path.display(),
+    )?;
+    Ok(())
+}
+
+fn split_line(data: &[u8]) -> Result<(&str, &[u8])> {
+    let newline = data
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| anyhow!("unexpected end of bundle"))?;
+    let line = std::str::from_utf8(&data[..newline])?;
+    Ok((line, &data[newline + 1..]))
+}
@@ -1,17 +1,52 @@
 use anyhow::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
 
-#[derive(Deserialize)]
+// Field order matters here: toml-rs serializes struct fields in
+// declaration order and errors if a scalar value is emitted after a table,
+// so `ssh_keys` (a plain array) must come before `repository` (an
+// array of tables) and `forge` (a table) or `to_file` fails on write-back.
+#[derive(Deserialize, Serialize)]
 pub struct Config {
+    /// Extra SSH private keys to try, beyond the default
+    /// `~/.ssh/id_ed25519` and `~/.ssh/id_rsa`.
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
     pub repository: Vec<Repository>,
+    /// Forge to pull the repository list from on `gat sync`.
+    #[serde(default)]
+    pub forge: Option<Forge>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Repository {
     pub name: Option<String>,
     pub location: String,
     pub description: Option<String>,
+    /// Clone URL, used by `gat clone` to materialize `location` when it
+    /// doesn't exist yet.
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ForgeKind {
+    Github,
+    Forgejo,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Forge {
+    #[serde(rename = "type")]
+    pub kind: ForgeKind,
+    /// API host, e.g. `api.github.com` or `git.example.org`.
+    pub host: String,
+    pub org: Option<String>,
+    pub user: Option<String>,
+    /// Name of the environment variable holding the API token.
+    pub token_env: String,
+    /// Directory new repos are cloned under; defaults to the current dir.
+    pub base_dir: Option<String>,
 }
 
 pub fn from_file(file: &str) -> Result<Config> {
@@ -20,6 +55,12 @@ pub fn from_file(file: &str) -> Result<Config> {
     Ok(config)
 }
 
+pub fn to_file(config: &Config, file: &str) -> Result<()> {
+    std::fs::write(file, toml::to_string_pretty(config)?)?;
+
+    Ok(())
+}
+
 impl Repository {
     pub fn name(&self) -> &str {
         match &self.name {
@@ -32,3 +73,46 @@ impl Repository {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_to_file_and_from_file() {
+        let config = Config {
+            ssh_keys: vec!["/home/me/.ssh/id_ed25519".to_string()],
+            repository: vec![Repository {
+                name: Some("gat".to_string()),
+                location: "/home/me/src/gat".to_string(),
+                description: Some("git automation tool".to_string()),
+                url: Some("git@github.com:eddix/gat.git".to_string()),
+            }],
+            forge: Some(Forge {
+                kind: ForgeKind::Github,
+                host: "api.github.com".to_string(),
+                org: Some("eddix".to_string()),
+                user: None,
+                token_env: "GAT_GITHUB_TOKEN".to_string(),
+                base_dir: Some("/home/me/src".to_string()),
+            }),
+        };
+
+        let path = std::env::temp_dir().join(format!(
+            "gat-config-roundtrip-{}.toml",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+
+        to_file(&config, path).expect("writing config back out should succeed");
+        let reloaded = from_file(path).expect("re-reading the written config should succeed");
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(reloaded.ssh_keys, config.ssh_keys);
+        assert_eq!(reloaded.repository.len(), config.repository.len());
+        assert_eq!(reloaded.repository[0].location, config.repository[0].location);
+        assert_eq!(reloaded.repository[0].url, config.repository[0].url);
+        assert!(reloaded.forge.is_some());
+        assert_eq!(reloaded.forge.unwrap().host, "api.github.com");
+    }
+}
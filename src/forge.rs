@@ -0,0 +1,150 @@
+use crate::config;
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+
+struct RemoteRepo {
+    name: String,
+    description: Option<String>,
+    url: String,
+}
+
+/// Which scope to list repos for, resolved once from `forge.org`/`forge.user`
+/// so both forge backends branch on it the same way.
+#[derive(Clone, Copy)]
+enum Owner<'a> {
+    Org(&'a str),
+    User(&'a str),
+}
+
+impl<'a> Owner<'a> {
+    fn from_forge(forge: &'a config::Forge) -> Option<Self> {
+        match (&forge.org, &forge.user) {
+            (Some(org), _) => Some(Owner::Org(org)),
+            (None, Some(user)) => Some(Owner::User(user)),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GithubRepo {
+    name: String,
+    description: Option<String>,
+    clone_url: String,
+}
+
+#[derive(Deserialize)]
+struct ForgejoRepo {
+    name: String,
+    description: Option<String>,
+    clone_url: String,
+}
+
+/// Fetches the repository list from `forge` and appends any repos not
+/// already present in `config.repository` (matched by clone url), filling
+/// in `name`, `description`, `url`, and a default `location` under
+/// `forge.base_dir`. Returns how many repositories were added.
+pub fn sync(config: &mut config::Config, forge: &config::Forge) -> Result<usize> {
+    let token = env::var(&forge.token_env)
+        .map_err(|_| anyhow!("environment variable {} is not set", forge.token_env))?;
+
+    let remote_repos = match forge.kind {
+        config::ForgeKind::Github => fetch_github(forge, &token)?,
+        config::ForgeKind::Forgejo => fetch_forgejo(forge, &token)?,
+    };
+
+    let known_urls: HashSet<String> = config
+        .repository
+        .iter()
+        .filter_map(|repository| repository.url.clone())
+        .collect();
+
+    let base_dir = forge.base_dir.as_deref().unwrap_or(".");
+    let mut added = 0;
+    for remote in remote_repos {
+        if known_urls.contains(&remote.url) {
+            continue;
+        }
+        config.repository.push(config::Repository {
+            name: Some(remote.name.clone()),
+            location: format!("{}/{}", base_dir, remote.name),
+            description: remote.description,
+            url: Some(remote.url),
+        });
+        added += 1;
+    }
+
+    Ok(added)
+}
+
+fn fetch_github(forge: &config::Forge, token: &str) -> Result<Vec<RemoteRepo>> {
+    let owner = Owner::from_forge(forge);
+    let mut repos = Vec::new();
+    let mut page = 1;
+    loop {
+        let url = match owner {
+            Some(Owner::Org(org)) => format!(
+                "https://{}/orgs/{}/repos?per_page=100&page={}",
+                forge.host, org, page
+            ),
+            Some(Owner::User(user)) => format!(
+                "https://{}/users/{}/repos?per_page=100&page={}",
+                forge.host, user, page
+            ),
+            None => format!("https://{}/user/repos?per_page=100&page={}", forge.host, page),
+        };
+        let response: Vec<GithubRepo> = ureq::get(&url)
+            .set("Authorization", &format!("Bearer {}", token))
+            .set("User-Agent", "gat")
+            .call()?
+            .into_json()?;
+        let page_len = response.len();
+        repos.extend(response.into_iter().map(|repo| RemoteRepo {
+            name: repo.name,
+            description: repo.description,
+            url: repo.clone_url,
+        }));
+        if page_len < 100 {
+            break;
+        }
+        page += 1;
+    }
+    Ok(repos)
+}
+
+fn fetch_forgejo(forge: &config::Forge, token: &str) -> Result<Vec<RemoteRepo>> {
+    let owner = Owner::from_forge(forge)
+        .ok_or_else(|| anyhow!("forge config needs either `org` or `user`"))?;
+
+    let mut repos = Vec::new();
+    let mut page = 1;
+    loop {
+        let url = match owner {
+            Owner::Org(org) => format!(
+                "https://{}/api/v1/orgs/{}/repos?limit=50&page={}",
+                forge.host, org, page
+            ),
+            Owner::User(user) => format!(
+                "https://{}/api/v1/users/{}/repos?limit=50&page={}",
+                forge.host, user, page
+            ),
+        };
+        let response: Vec<ForgejoRepo> = ureq::get(&url)
+            .set("Authorization", &format!("token {}", token))
+            .call()?
+            .into_json()?;
+        let page_len = response.len();
+        repos.extend(response.into_iter().map(|repo| RemoteRepo {
+            name: repo.name,
+            description: repo.description,
+            url: repo.clone_url,
+        }));
+        if page_len < 50 {
+            break;
+        }
+        page += 1;
+    }
+    Ok(repos)
+}
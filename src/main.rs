@@ -1,16 +1,29 @@
+mod auth;
+mod bundle;
 mod config;
+mod forge;
 
 use anyhow::{Result, anyhow};
+use auth::AuthCache;
 use clap::{Parser, Subcommand};
 use colored::Colorize;
-use git2::{AutotagOption, Cred, ErrorCode, FetchOptions, RemoteCallbacks, RemoteUpdateFlags, Repository, StatusOptions};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{AutotagOption, ErrorCode, FetchOptions, RemoteCallbacks, RemoteUpdateFlags, Repository, StatusOptions};
+use std::cell::RefCell;
+use std::io;
 use std::io::Write;
-use std::{env, io};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[derive(Parser)]
 struct Gat {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Number of repositories to process concurrently
+    #[clap(short = 'j', long = "jobs", global = true)]
+    jobs: Option<usize>,
 }
 
 #[derive(Subcommand)]
@@ -19,12 +32,17 @@ enum Commands {
     Status,
     Fetch,
     Pull,
+    Clone,
+    Sync,
+    Bundle,
+    Unbundle,
 }
 
-fn print_title(repository: &config::Repository) -> Result<()> {
+fn print_title(repository: &config::Repository, out: &mut dyn Write) -> Result<()> {
     let repo = Repository::open(&repository.location)?;
     if repo.is_bare() {
-        eprintln!(
+        writeln!(
+            out,
             "{}: cannot use bare repository\n{} - {}\n",
             repository.name().red().bold(),
             repository
@@ -33,7 +51,7 @@ fn print_title(repository: &config::Repository) -> Result<()> {
                 .unwrap_or(&"No description".to_string())
                 .yellow(),
             &repository.location.blue(),
-        );
+        )?;
         return Err(anyhow!("Not a bare repository"));
     }
     let head = match repo.head() {
@@ -42,12 +60,13 @@ fn print_title(repository: &config::Repository) -> Result<()> {
             None
         }
         Err(e) => {
-            eprintln!("can't get HEAD: {}", e);
+            writeln!(out, "can't get HEAD: {}", e)?;
             return Err(e.into())
         }
     };
 
-    println!(
+    writeln!(
+        out,
         "{}({}): {}\n{}",
         repository.name().green().bold(),
         head.as_ref().and_then(|h| h.shorthand()).unwrap_or("no branch").cyan(),
@@ -57,23 +76,80 @@ fn print_title(repository: &config::Repository) -> Result<()> {
             .as_ref()
             .unwrap_or(&"No description".to_string())
             .white().italic(),
-    );
+    )?;
     Ok(())
 }
 
-fn status(repository: config::Repository) -> Result<()> {
-    print_title(&repository)?;
-    let repo = Repository::open(repository.location)?;
+/// Prints a compact one-line divergence summary above the per-file status
+/// list: ahead/behind counts against the branch's upstream, a conflict
+/// count, and how many stashes are sitting on the repo. Silent if there's
+/// nothing to report.
+fn print_divergence_summary(repo: &mut Repository, out: &mut dyn Write) -> Result<()> {
+    let mut parts = Vec::new();
+
+    if let Ok(head) = repo.head() {
+        let local_oid = head.target();
+        if head.is_branch() {
+            if let (Some(local_oid), Ok(upstream)) =
+                (local_oid, git2::Branch::wrap(head).upstream())
+            {
+                if let Some(upstream_oid) = upstream.get().target() {
+                    let (ahead, behind) = repo.graph_ahead_behind(local_oid, upstream_oid)?;
+                    if ahead > 0 && behind > 0 {
+                        parts.push(format!("\u{21d5} \u{21e1}{} \u{21e3}{}", ahead, behind));
+                    } else if ahead > 0 {
+                        parts.push(format!("\u{21e1}{}", ahead));
+                    } else if behind > 0 {
+                        parts.push(format!("\u{21e3}{}", behind));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut opts = StatusOptions::new();
+    opts.include_ignored(false);
+    opts.include_untracked(true);
+    let conflicted = repo
+        .statuses(Some(&mut opts))?
+        .iter()
+        .filter(|entry| entry.status().contains(git2::Status::CONFLICTED))
+        .count();
+    if conflicted > 0 {
+        parts.push(format!("\u{2718}{}", conflicted));
+    }
+
+    let mut stash_count = 0usize;
+    repo.stash_foreach(|_, _, _| {
+        stash_count += 1;
+        true
+    })?;
+    if stash_count > 0 {
+        parts.push(format!("stash:{}", stash_count));
+    }
+
+    if !parts.is_empty() {
+        writeln!(out, "{}", parts.join(" "))?;
+    }
+
+    Ok(())
+}
+
+fn status(repository: config::Repository, _auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<()> {
+    print_title(&repository, out)?;
+    let mut repo = Repository::open(repository.location)?;
     if repo.is_bare() {
-        return Err(anyhow::anyhow!("cannot use bare repository").into());
+        return Err(anyhow::anyhow!("cannot use bare repository"));
     }
 
+    print_divergence_summary(&mut repo, out)?;
+
     let mut opts = StatusOptions::new();
     opts.include_ignored(false);
     opts.include_untracked(true);
     let status = repo.statuses(Some(&mut opts))?;
     if status.iter().len() == 0 {
-        println!("{}", "Nothing changed in this repository".green());
+        writeln!(out, "{}", "Nothing changed in this repository".green())?;
         return Ok(())
     }
     for status in repo.statuses(Some(&mut opts))?.iter() {
@@ -102,61 +178,58 @@ fn status(repository: config::Repository) -> Result<()> {
             istatus = '!';
             wstatus = '!';
         }
-        println!(
+        writeln!(
+            out,
             "  - {}{}  {}",
             istatus,
             wstatus,
             status.path().unwrap_or("None"),
-        );
+        )?;
     }
     Ok(())
 }
 
-fn fetch(repository: config::Repository) -> Result<()> {
-    print_title(&repository)?;
-    let repo = Repository::open(repository.location)?;
-    if repo.is_bare() {
-        return Err(anyhow!("cannot use bare repository").into());
-    }
+fn do_fetch(repo: &Repository, auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<()> {
     let mut cb = RemoteCallbacks::new();
-    cb.credentials(|_url, username_from_url, _allowed_types| {
-        Cred::ssh_key(
-            username_from_url.unwrap(),
-            None,
-            std::path::Path::new(&format!("{}/.ssh/id_rsa", env!("HOME"))),
-            None,
-        )
-    });
+    let (credentials, offered) = auth.credentials_callback();
+    cb.credentials(credentials);
+
+    // The callbacks below run synchronously inside `remote.download`, one at
+    // a time, so a RefCell is enough to let them all write into the same
+    // per-repo buffer without needing `out` to be 'static.
+    let out = RefCell::new(out);
     cb.sideband_progress(|data| {
-        print!("{}", String::from_utf8_lossy(data));
-        io::stdout().flush().unwrap();
+        let _ = out.borrow_mut().write_all(data);
         true
     });
     cb.update_tips(|refname, a, b| {
+        let mut out = out.borrow_mut();
         if a.is_zero() {
-            println!("[new]     {:20} {}", b, refname);
+            let _ = writeln!(out, "[new]     {:20} {}", b, refname);
         } else {
-            println!("[updated] {:10}..{:10} {}", a, b, refname);
+            let _ = writeln!(out, "[updated] {:10}..{:10} {}", a, b, refname);
         }
         true
     });
     cb.transfer_progress(|stats| {
+        let mut out = out.borrow_mut();
         if stats.received_objects() == stats.total_objects() {
-            print!(
+            let _ = write!(
+                out,
                 "Resolving deltas {}/{}\r",
                 stats.indexed_deltas(),
                 stats.total_deltas()
             );
         } else if stats.total_objects() > 0 {
-            print!(
+            let _ = write!(
+                out,
                 "Received {}/{} objects ({}) in {} bytes\r",
                 stats.received_objects(),
                 stats.total_objects(),
                 stats.indexed_objects(),
                 stats.received_bytes()
-            )
+            );
         }
-        io::stdout().flush().unwrap();
         true
     });
 
@@ -165,25 +238,36 @@ fn fetch(repository: config::Repository) -> Result<()> {
 
     let mut remote = repo.find_remote("origin")?;
     remote.download(&[] as &[&str], Some(&mut fo))?;
+    // Drop `fo` (and the `cb` closures borrowing `out`) explicitly so the
+    // borrow ends here instead of at the end of the function, where its
+    // destructor would otherwise keep `out` borrowed past `into_inner()`.
+    drop(fo);
+    // The download above succeeded, so whatever credential method was last
+    // offered is the one that actually authenticated; cache it.
+    auth.confirm(&offered);
+
+    let out = out.into_inner();
 
     {
         let stats = remote.stats();
         if stats.local_objects() > 0 {
-            println!(
-                "\rReceived {}/{} objects in {} bytes (used {} local \
+            writeln!(
+                out,
+                "Received {}/{} objects in {} bytes (used {} local \
              objects)",
                 stats.indexed_objects(),
                 stats.total_objects(),
                 stats.received_bytes(),
                 stats.local_objects()
-            );
+            )?;
         } else {
-            println!(
-                "\rReceived {}/{} objects in {} bytes",
+            writeln!(
+                out,
+                "Received {}/{} objects in {} bytes",
                 stats.indexed_objects(),
                 stats.total_objects(),
                 stats.received_bytes()
-            )
+            )?;
         }
     }
 
@@ -198,40 +282,245 @@ fn fetch(repository: config::Repository) -> Result<()> {
     Ok(())
 }
 
-fn pull(repository: config::Repository) -> Result<()> {
-    print_title(&repository)?;
+fn fetch(repository: config::Repository, auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<()> {
+    print_title(&repository, out)?;
+    let repo = Repository::open(repository.location)?;
+    if repo.is_bare() {
+        return Err(anyhow!("cannot use bare repository"));
+    }
+    do_fetch(&repo, auth, out)
+}
+
+fn pull(repository: config::Repository, auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<()> {
+    print_title(&repository, out)?;
+    let repo = Repository::open(&repository.location)?;
+    if repo.is_bare() {
+        return Err(anyhow!("cannot use bare repository"));
+    }
+
+    do_fetch(&repo, auth, out)?;
+
+    let head = repo.head()?;
+    let branch_name = head
+        .shorthand()
+        .ok_or_else(|| anyhow!("HEAD is not a branch"))?
+        .to_string();
+
+    let fetch_head = repo.find_reference("FETCH_HEAD")?;
+    let annotated_commit = repo.reference_to_annotated_commit(&fetch_head)?;
+
+    let (analysis, _) = repo.merge_analysis(&[&annotated_commit])?;
+
+    if analysis.is_up_to_date() {
+        writeln!(out, "{}", "Already up to date".green())?;
+    } else if analysis.is_fast_forward() {
+        let refname = format!("refs/heads/{}", branch_name);
+        let target = annotated_commit.id();
+        let mut local_ref = repo.find_reference(&refname)?;
+        local_ref.set_target(target, "gat: fast-forward pull")?;
+        repo.set_head(&refname)?;
+
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        repo.checkout_head(Some(&mut checkout))?;
+
+        writeln!(out, "{} to {}", "Fast-forwarded".green(), target)?;
+    } else {
+        writeln!(
+            out,
+            "{}",
+            "Diverged from upstream; merge manually".yellow()
+        )?;
+    }
 
     Ok(())
 }
 
+/// Clones `repository.url` into `repository.location` if it doesn't exist
+/// yet, reusing the same credential cache and transfer progress reporting
+/// as `fetch`. Returns `true` if a clone happened, `false` if it was
+/// skipped because the location already exists.
+fn clone_repo(repository: config::Repository, auth: &Arc<AuthCache>, out: &mut dyn Write) -> Result<bool> {
+    if std::path::Path::new(&repository.location).exists() {
+        writeln!(
+            out,
+            "{}: already exists, skipping",
+            repository.name().yellow(),
+        )?;
+        return Ok(false);
+    }
+
+    let url = repository
+        .url
+        .as_ref()
+        .ok_or_else(|| anyhow!("{}: no url configured", repository.name()))?;
+
+    writeln!(
+        out,
+        "{}: cloning {}",
+        repository.name().green().bold(),
+        url.blue(),
+    )?;
+
+    let mut cb = RemoteCallbacks::new();
+    let (credentials, offered) = auth.credentials_callback();
+    cb.credentials(credentials);
+
+    let out = RefCell::new(out);
+    cb.sideband_progress(|data| {
+        let _ = out.borrow_mut().write_all(data);
+        true
+    });
+    cb.transfer_progress(|stats| {
+        let mut out = out.borrow_mut();
+        if stats.received_objects() == stats.total_objects() {
+            let _ = write!(
+                out,
+                "Resolving deltas {}/{}\r",
+                stats.indexed_deltas(),
+                stats.total_deltas()
+            );
+        } else if stats.total_objects() > 0 {
+            let _ = write!(
+                out,
+                "Received {}/{} objects ({}) in {} bytes\r",
+                stats.received_objects(),
+                stats.total_objects(),
+                stats.indexed_objects(),
+                stats.received_bytes()
+            );
+        }
+        true
+    });
+
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(cb);
+
+    let mut builder = RepoBuilder::new();
+    builder.fetch_options(fo);
+    builder.clone(url, std::path::Path::new(&repository.location))?;
+    // See do_fetch: drop explicitly so `out`'s borrow doesn't extend to the
+    // end of the function via `builder`'s destructor.
+    drop(builder);
+    // The clone above succeeded, so whatever credential method was last
+    // offered is the one that actually authenticated; cache it.
+    auth.confirm(&offered);
+
+    let out = out.into_inner();
+    writeln!(out, "{}", "done".green())?;
+
+    Ok(true)
+}
+
+/// Runs `task` over `repositories` on a bounded pool of `jobs` worker
+/// threads, then flushes each repo's buffered output to stdout in the
+/// original config order so concurrent network callbacks can't interleave
+/// on the terminal. Returns each task's `Ok` value, in config order,
+/// dropping entries that errored (the error itself is printed to stderr).
+fn run_parallel<F, T>(repositories: Vec<config::Repository>, jobs: usize, auth: Arc<AuthCache>, task: F) -> Vec<T>
+where
+    F: Fn(config::Repository, &Arc<AuthCache>, &mut dyn Write) -> Result<T> + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let task = Arc::new(task);
+    let total = repositories.len();
+
+    let (job_tx, job_rx) = mpsc::channel::<(usize, config::Repository)>();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    for job in repositories.into_iter().enumerate() {
+        job_tx.send(job).unwrap();
+    }
+    drop(job_tx);
+
+    let (res_tx, res_rx) = mpsc::channel::<(usize, Vec<u8>, Result<T>)>();
+    let workers: Vec<_> = (0..jobs.max(1))
+        .map(|_| {
+            let job_rx = Arc::clone(&job_rx);
+            let res_tx = res_tx.clone();
+            let task = Arc::clone(&task);
+            let auth = Arc::clone(&auth);
+            thread::spawn(move || {
+                while let Ok((index, repository)) = {
+                    let job_rx = job_rx.lock().unwrap();
+                    job_rx.recv()
+                } {
+                    let mut buf = Vec::new();
+                    let result = task(repository, &auth, &mut buf);
+                    let _ = res_tx.send((index, buf, result));
+                }
+            })
+        })
+        .collect();
+    drop(res_tx);
+
+    let mut outputs: Vec<Option<(Vec<u8>, Result<T>)>> = (0..total).map(|_| None).collect();
+    for (index, buf, result) in res_rx {
+        outputs[index] = Some((buf, result));
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    let mut stdout = io::stdout();
+    let mut results = Vec::with_capacity(total);
+    for entry in outputs.into_iter().flatten() {
+        let (buf, result) = entry;
+        let _ = stdout.write_all(&buf);
+        match result {
+            Ok(value) => results.push(value),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+    results
+}
+
 fn main() {
-    let config = config::from_file(format!("{}/.gatconfig", env!("HOME")).as_str()).unwrap();
-    match Gat::parse().command {
+    let config_path = format!("{}/.gatconfig", env!("HOME"));
+    let mut config = config::from_file(&config_path).unwrap();
+    let args = Gat::parse();
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    let auth = Arc::new(AuthCache::new(&config.ssh_keys));
+
+    match args.command {
         Commands::List => {
+            let mut stdout = io::stdout();
             for repo in config.repository {
-                let _ = print_title(&repo);
+                let _ = print_title(&repo, &mut stdout);
             }
         }
         Commands::Status => {
-            for repo in config.repository {
-                if let Err(err) = status(repo) {
-                    eprintln!("{:?}", err);
-                }
-            }
+            run_parallel(config.repository, jobs, auth, status);
         }
         Commands::Fetch => {
-            for repo in config.repository {
-                if let Err(err) = fetch(repo) {
-                    eprintln!("{:?}", err)
-                }
-            }
+            run_parallel(config.repository, jobs, auth, fetch);
         }
         Commands::Pull => {
-            for repo in config.repository {
-                if let Err(err) = pull(repo) {
-                    eprintln!("{:?}", err)
-                }
-            }
+            run_parallel(config.repository, jobs, auth, pull);
+        }
+        Commands::Clone => {
+            let cloned: Vec<bool> = run_parallel(config.repository, jobs, auth, clone_repo);
+            let skipped = cloned.iter().filter(|&&c| !c).count();
+            let cloned = cloned.iter().filter(|&&c| c).count();
+            println!("{} cloned, {} skipped", cloned, skipped);
+        }
+        Commands::Sync => match config.forge.clone() {
+            Some(forge) => match forge::sync(&mut config, &forge) {
+                Ok(added) => match config::to_file(&config, &config_path) {
+                    Ok(()) => println!("{} new repositories added", added),
+                    Err(err) => eprintln!("{:?}", err),
+                },
+                Err(err) => eprintln!("{:?}", err),
+            },
+            None => eprintln!("no [forge] section configured in .gatconfig"),
+        },
+        Commands::Bundle => {
+            run_parallel(config.repository, jobs, auth, bundle::bundle_repo);
+        }
+        Commands::Unbundle => {
+            run_parallel(config.repository, jobs, auth, bundle::unbundle_repo);
         }
     }
 }